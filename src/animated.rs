@@ -0,0 +1,237 @@
+use crate::decay::DecayParams;
+use crate::BitCrush;
+use http_types::Mime;
+use image::DynamicImage;
+use std::error::Error;
+use std::io::Read;
+
+/// Caps how many frames we'll decode and decay from an animated upload,
+/// so a long GIF/MP4 can't pin the CPU indefinitely.
+pub const MAX_FRAMES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimatedFormat {
+    Gif,
+    Mp4,
+}
+
+/// A single decoded frame paired with how long it should be displayed.
+pub struct Frame {
+    pub image: DynamicImage,
+    pub delay_ms: u32,
+}
+
+/// Sniffs the decoded bytes for the GIF/MP4 signatures `handle_upload`
+/// needs to route into the animated pipeline instead of the single-image
+/// `bitcrush` path.
+pub fn detect_animated(bytes: &[u8]) -> Option<AnimatedFormat> {
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some(AnimatedFormat::Gif)
+    } else if bytes.len() > 12 && &bytes[4..8] == b"ftyp" {
+        Some(AnimatedFormat::Mp4)
+    } else {
+        None
+    }
+}
+
+fn decode_gif_frames(bytes: &[u8]) -> Result<Vec<Frame>, Box<dyn Error>> {
+    use image::AnimationDecoder;
+
+    let decoder = image::gif::Decoder::new(bytes)?;
+    let mut frames = Vec::new();
+    for frame in decoder.into_frames().take(MAX_FRAMES) {
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_ms = if denom == 0 { 0 } else { numer / denom };
+        frames.push(Frame {
+            image: DynamicImage::ImageRgba8(frame.into_buffer()),
+            delay_ms,
+        });
+    }
+    Ok(frames)
+}
+
+/// `ffmpeg-next`'s safe API only demuxes/muxes real files, not in-memory
+/// byte slices (doing that requires a custom `AVIOContext` behind
+/// `unsafe` FFI). Uploads are small enough that round-tripping through a
+/// temp file is the pragmatic way to reuse that safe API.
+fn decode_mp4_frames(bytes: &[u8]) -> Result<(Vec<Frame>, ffmpeg_next::Rational), Box<dyn Error>> {
+    let input_file = tempfile::Builder::new().suffix(".mp4").tempfile()?;
+    std::fs::write(input_file.path(), bytes)?;
+
+    let mut input = ffmpeg_next::format::input(&input_file.path())?;
+    let stream = input
+        .streams()
+        .best(ffmpeg_next::media::Type::Video)
+        .ok_or("no video stream in upload")?;
+    let stream_index = stream.index();
+    let framerate = stream.rate();
+
+    let context_decoder =
+        ffmpeg_next::codec::context::Context::from_parameters(stream.parameters())?;
+    let mut decoder = context_decoder.decoder().video()?;
+    let mut scaler = ffmpeg_next::software::scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        ffmpeg_next::format::Pixel::RGBA,
+        decoder.width(),
+        decoder.height(),
+        ffmpeg_next::software::scaling::Flags::BILINEAR,
+    )?;
+
+    let delay_ms = (1000.0 * framerate.denominator() as f64 / framerate.numerator() as f64) as u32;
+    let mut frames = Vec::new();
+
+    'demux: for (packet_stream, packet) in input.packets() {
+        if packet_stream.index() != stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        let mut decoded = ffmpeg_next::frame::Video::empty();
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let mut rgba = ffmpeg_next::frame::Video::empty();
+            scaler.run(&decoded, &mut rgba)?;
+
+            let buffer =
+                image::ImageBuffer::from_raw(rgba.width(), rgba.height(), rgba.data(0).to_vec())
+                    .ok_or("could not build frame buffer from decoded video frame")?;
+            frames.push(Frame {
+                image: DynamicImage::ImageRgba8(buffer),
+                delay_ms,
+            });
+            if frames.len() >= MAX_FRAMES {
+                break 'demux;
+            }
+        }
+    }
+
+    Ok((frames, framerate))
+}
+
+/// Runs the existing [`BitCrush`] degradation independently over every
+/// decoded frame of an animated upload, then re-muxes into the same
+/// container it came from. Per-frame delays are preserved and the
+/// frame count is capped by [`MAX_FRAMES`] to bound CPU.
+pub fn bitcrush_animated(
+    format: AnimatedFormat,
+    bytes: &[u8],
+    params: &DecayParams,
+) -> Result<(Vec<u8>, Mime), Box<dyn Error>> {
+    match format {
+        AnimatedFormat::Gif => {
+            let frames = decode_gif_frames(bytes)?;
+            let out = encode_gif(frames, params)?;
+            Ok((out, crate::mimes::gif()))
+        }
+        AnimatedFormat::Mp4 => {
+            let (frames, framerate) = decode_mp4_frames(bytes)?;
+            let out = encode_mp4(frames, framerate, params)?;
+            Ok((out, crate::mimes::mp4()))
+        }
+    }
+}
+
+fn encode_gif(frames: Vec<Frame>, params: &DecayParams) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = image::gif::Encoder::new(&mut out);
+        for frame in frames {
+            let crushed = frame.image.bitcrush(params)?;
+            let gif_frame = image::Frame::from_parts(
+                crushed.to_rgba(),
+                0,
+                0,
+                image::Delay::from_saturating_duration(std::time::Duration::from_millis(
+                    frame.delay_ms as u64,
+                )),
+            );
+            encoder.encode_frame(gif_frame)?;
+        }
+    }
+    Ok(out)
+}
+
+fn encode_mp4(
+    frames: Vec<Frame>,
+    framerate: ffmpeg_next::Rational,
+    params: &DecayParams,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (width, height) = frames
+        .first()
+        .map(|f| (f.image.width(), f.image.height()))
+        .ok_or("no frames decoded from MP4 upload")?;
+
+    let output_file = tempfile::Builder::new().suffix(".mp4").tempfile()?;
+    let mut octx = ffmpeg_next::format::output(&output_file.path())?;
+
+    let codec = ffmpeg_next::encoder::find(ffmpeg_next::codec::Id::H264)
+        .ok_or("no H.264 encoder available")?;
+    let mut ost = octx.add_stream(codec)?;
+
+    let mut encoder = ffmpeg_next::codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    encoder.set_width(width);
+    encoder.set_height(height);
+    encoder.set_format(ffmpeg_next::format::Pixel::YUV420P);
+    encoder.set_time_base(framerate.invert());
+    encoder.set_frame_rate(Some(framerate));
+    if octx
+        .format()
+        .flags()
+        .contains(ffmpeg_next::format::flag::Flags::GLOBAL_HEADER)
+    {
+        encoder.set_flags(ffmpeg_next::codec::flag::Flags::GLOBAL_HEADER);
+    }
+    let mut encoder = encoder.open_as(codec)?;
+    ost.set_parameters(&encoder);
+    let stream_index = ost.index();
+
+    let mut scaler = ffmpeg_next::software::scaling::Context::get(
+        ffmpeg_next::format::Pixel::RGBA,
+        width,
+        height,
+        ffmpeg_next::format::Pixel::YUV420P,
+        width,
+        height,
+        ffmpeg_next::software::scaling::Flags::BILINEAR,
+    )?;
+
+    octx.write_header()?;
+
+    for (index, frame) in frames.into_iter().enumerate() {
+        let crushed = frame.image.bitcrush(params)?;
+        let rgba_buffer = crushed.to_rgba();
+
+        let mut rgba_frame = ffmpeg_next::frame::Video::new(
+            ffmpeg_next::format::Pixel::RGBA,
+            rgba_buffer.width(),
+            rgba_buffer.height(),
+        );
+        rgba_frame.data_mut(0).copy_from_slice(rgba_buffer.as_raw());
+
+        let mut yuv_frame = ffmpeg_next::frame::Video::empty();
+        scaler.run(&rgba_frame, &mut yuv_frame)?;
+        yuv_frame.set_pts(Some(index as i64));
+
+        encoder.send_frame(&yuv_frame)?;
+        let mut packet = ffmpeg_next::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(stream_index);
+            packet.write_interleaved(&mut octx)?;
+        }
+    }
+
+    encoder.send_eof()?;
+    let mut packet = ffmpeg_next::Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(stream_index);
+        packet.write_interleaved(&mut octx)?;
+    }
+    octx.write_trailer()?;
+
+    let mut out = Vec::new();
+    std::fs::File::open(output_file.path())?.read_to_end(&mut out)?;
+    Ok(out)
+}
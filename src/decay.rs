@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Tunable knobs for [`crate::BitCrush::bitcrush`]. Defaults reproduce
+/// the degradation the service always applied before these became
+/// configurable.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DecayParams {
+    pub passes: u8,
+    pub quality_min: u8,
+    pub quality_max: u8,
+    pub rotate180: bool,
+    pub huerotate: bool,
+    pub scale_min: f32,
+    pub scale_max: f32,
+}
+
+impl Default for DecayParams {
+    fn default() -> Self {
+        DecayParams {
+            passes: 2,
+            quality_min: 10,
+            quality_max: 30,
+            rotate180: true,
+            huerotate: true,
+            scale_min: 0.5,
+            scale_max: 2.0,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecayParamsError {
+    #[error("passes must be between 1 and 10, got {0}")]
+    InvalidPasses(u8),
+
+    #[error("quality_min/quality_max must satisfy 1 <= min <= max <= 100, got {0}/{1}")]
+    InvalidQualityRange(u8, u8),
+
+    #[error("scale_min/scale_max must satisfy 0 < min < max, got {0}/{1}")]
+    InvalidScaleRange(f32, f32),
+
+    #[error("scale_min/scale_max must be finite numbers, got {0}/{1}")]
+    NonFiniteScaleRange(f32, f32),
+}
+
+impl DecayParams {
+    pub fn validate(&self) -> Result<(), DecayParamsError> {
+        if self.passes == 0 || self.passes > 10 {
+            return Err(DecayParamsError::InvalidPasses(self.passes));
+        }
+        if self.quality_min == 0 || self.quality_min > self.quality_max || self.quality_max > 100 {
+            return Err(DecayParamsError::InvalidQualityRange(
+                self.quality_min,
+                self.quality_max,
+            ));
+        }
+        // NaN compares false against every bound below and an infinite
+        // `scale_max` satisfies them, so either would otherwise sail
+        // through and panic `rng.gen_range` (NaN) or overflow the resize
+        // dimensions (infinity) in `bitcrush`.
+        if !self.scale_min.is_finite() || !self.scale_max.is_finite() {
+            return Err(DecayParamsError::NonFiniteScaleRange(
+                self.scale_min,
+                self.scale_max,
+            ));
+        }
+        // `bitcrush` feeds this into `rng.gen_range(scale_min, scale_max)`,
+        // an exclusive-upper-bound range, so equal bounds must be rejected
+        // here rather than silently panicking at request time.
+        if self.scale_min <= 0.0 || self.scale_min >= self.scale_max {
+            return Err(DecayParamsError::InvalidScaleRange(
+                self.scale_min,
+                self.scale_max,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Builds `DecayParams` from the `/upload` query string, falling
+    /// back to [`DecayParams::default`] for any key that's absent.
+    pub fn from_query(query: &HashMap<String, String>) -> Self {
+        let defaults = DecayParams::default();
+
+        let parse_or = |key: &str, default: u8| -> u8 {
+            query
+                .get(key)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let parse_or_f32 = |key: &str, default: f32| -> f32 {
+            query
+                .get(key)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let parse_bool_or = |key: &str, default: bool| -> bool {
+            query
+                .get(key)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        DecayParams {
+            passes: parse_or("passes", defaults.passes),
+            quality_min: parse_or("quality_min", defaults.quality_min),
+            quality_max: parse_or("quality_max", defaults.quality_max),
+            rotate180: parse_bool_or("rotate180", defaults.rotate180),
+            huerotate: parse_bool_or("huerotate", defaults.huerotate),
+            scale_min: parse_or_f32("scale_min", defaults.scale_min),
+            scale_max: parse_or_f32("scale_max", defaults.scale_max),
+        }
+    }
+}
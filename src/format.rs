@@ -0,0 +1,96 @@
+use crate::mimes;
+use http_types::Mime;
+use image::DynamicImage;
+use std::error::Error;
+
+/// The formats `serve_image` is willing to transcode a stored JPEG into,
+/// chosen by `negotiate` from an `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Jpeg,
+    WebP,
+    Avif,
+    Jxl,
+}
+
+impl OutputFormat {
+    /// Every format that is cached as a transcoded variant (JPEG is the
+    /// stored original and is never cached as one), so storage backends
+    /// can sweep all of an id's cached variants (e.g. on delete) without
+    /// hardcoding the list.
+    pub const ALL: [OutputFormat; 3] = [OutputFormat::WebP, OutputFormat::Avif, OutputFormat::Jxl];
+
+    pub fn mime(self) -> Mime {
+        match self {
+            OutputFormat::Jpeg => mimes::jpeg(),
+            OutputFormat::WebP => mimes::webp(),
+            OutputFormat::Avif => mimes::avif(),
+            OutputFormat::Jxl => mimes::jxl(),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jxl => "jxl",
+        }
+    }
+
+    pub fn encode(self, img: &DynamicImage) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            OutputFormat::Jpeg => {
+                let mut out = Vec::new();
+                let mut encoder =
+                    image::jpeg::JPEGEncoder::new_with_quality(&mut out, crate::JPEG_QUALITY);
+                encoder.encode_image(img)?;
+                Ok(out)
+            }
+            OutputFormat::WebP => {
+                let rgba = img.to_rgba();
+                let (width, height) = (rgba.width(), rgba.height());
+                let encoder = webp::Encoder::from_rgba(&rgba, width, height);
+                Ok(encoder.encode(80.0).to_vec())
+            }
+            OutputFormat::Avif => {
+                use rgb::FromSlice;
+
+                let rgba = img.to_rgba();
+                let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+                let pixels = rgba.as_raw().as_rgba();
+                let buffer = ravif::Img::new(pixels, width, height);
+                let result = ravif::Encoder::new().encode_rgba(buffer)?;
+                Ok(result.avif_file)
+            }
+            OutputFormat::Jxl => {
+                let rgba = img.to_rgba();
+                let (width, height) = (rgba.width(), rgba.height());
+                let mut encoder = jpegxl_rs::encoder_builder().build()?;
+                let result: jpegxl_rs::encode::EncoderResult<u8> =
+                    encoder.encode::<u8, u8>(rgba.as_raw(), width, height)?;
+                Ok(result.data)
+            }
+        }
+    }
+}
+
+/// Picks the best output format from an `Accept` header, preferring
+/// whichever of webp/avif/jxl the client lists, and falling back to the
+/// JPEG that is already sitting in storage.
+pub fn negotiate(accept: Option<&str>) -> OutputFormat {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return OutputFormat::Jpeg,
+    };
+
+    if accept.contains("image/webp") {
+        OutputFormat::WebP
+    } else if accept.contains("image/avif") {
+        OutputFormat::Avif
+    } else if accept.contains("image/jxl") {
+        OutputFormat::Jxl
+    } else {
+        OutputFormat::Jpeg
+    }
+}
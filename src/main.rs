@@ -1,32 +1,132 @@
+use bytes::Buf;
+use futures::{StreamExt, TryStreamExt};
 use http_types::Mime;
 use image::{imageops::FilterType, jpeg::JPEGEncoder, DynamicImage, GenericImageView};
 use liquid::{Object, Template};
 use rand::Rng;
 use serde::Serialize;
-use std::{collections::HashMap, error::Error, net::SocketAddr, sync::Arc};
-use tokio::{fs::read_to_string, sync::RwLock};
+use std::{collections::HashMap, error::Error, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::fs::read_to_string;
+use tracing::Instrument;
 use ulid::Ulid;
-use warp::Filter;
+use warp::{multipart::FormData, Filter};
 
+mod animated;
+mod decay;
+mod format;
 mod mimes;
+mod storage;
+
+use animated::{bitcrush_animated, detect_animated};
+use decay::DecayParams;
+use format::{negotiate, OutputFormat};
+use storage::{MemoryStorage, S3Config, S3Storage, Storage};
 
 pub type TemplateMap = HashMap<String, Template>;
 pub const JPEG_QUALITY: u8 = 25;
 
 struct State {
     templates: TemplateMap,
-    images: RwLock<HashMap<Ulid, Image>>,
+    images: Box<dyn Storage>,
+    upload_limits: UploadLimits,
 }
 
 impl State {
-    fn new(templates: TemplateMap) -> Self {
+    fn new(templates: TemplateMap, images: Box<dyn Storage>, upload_limits: UploadLimits) -> Self {
         State {
             templates,
-            images: Default::default(),
+            images,
+            upload_limits,
+        }
+    }
+}
+
+/// Size limits applied to multipart `/upload` requests.
+#[derive(Debug, Clone, Copy)]
+struct UploadLimits {
+    max_part_size: u64,
+    max_total_size: u64,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        UploadLimits {
+            max_part_size: 10 * 1024 * 1024,
+            max_total_size: 50 * 1024 * 1024,
         }
     }
 }
 
+/// Reads `UPLOAD_MAX_PART_BYTES`/`UPLOAD_MAX_TOTAL_BYTES` from the
+/// environment, falling back to sane defaults for whichever is unset.
+fn upload_limits_from_env() -> UploadLimits {
+    let defaults = UploadLimits::default();
+    UploadLimits {
+        max_part_size: std::env::var("UPLOAD_MAX_PART_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.max_part_size),
+        max_total_size: std::env::var("UPLOAD_MAX_TOTAL_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(defaults.max_total_size),
+    }
+}
+
+/// Picks a `Storage` backend from the environment: in-memory unless
+/// `STORAGE_BACKEND=s3`, in which case `S3_BUCKET`/`S3_REGION`/
+/// `S3_ACCESS_KEY`/`S3_SECRET_KEY` (and optionally `S3_ENDPOINT` for
+/// MinIO-style endpoints) configure the bucket.
+fn storage_from_env() -> Result<Box<dyn Storage>, Box<dyn Error>> {
+    match std::env::var("STORAGE_BACKEND").as_deref() {
+        Ok("s3") => {
+            let config = S3Config {
+                bucket: std::env::var("S3_BUCKET")?,
+                region: std::env::var("S3_REGION")?,
+                access_key: std::env::var("S3_ACCESS_KEY")?,
+                secret_key: std::env::var("S3_SECRET_KEY")?,
+                endpoint: std::env::var("S3_ENDPOINT").ok(),
+            };
+            Ok(Box::new(S3Storage::new(config)?))
+        }
+        _ => Ok(Box::new(MemoryStorage::default())),
+    }
+}
+
+/// How often the TTL sweep wakes up to check for expired images. Kept
+/// well below any reasonable TTL so eviction is timely without scanning
+/// constantly.
+const TTL_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reads `IMAGE_TTL_SECS` from the environment, if set, and spawns a
+/// background task that periodically evicts images whose Ulid-embedded
+/// creation timestamp is older than that TTL.
+fn spawn_ttl_eviction(state: Arc<State>) {
+    let ttl = match std::env::var("IMAGE_TTL_SECS").ok().and_then(|s| s.parse::<u64>().ok()) {
+        Some(secs) => Duration::from_secs(secs),
+        None => return,
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TTL_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+
+            for id in state.images.ids().await {
+                let age = now.saturating_sub(Duration::from_millis(id.timestamp_ms()));
+                if age > ttl {
+                    state.images.delete(id).await;
+                    tracing::info!(%id, "evicted expired image");
+                }
+            }
+        }
+    });
+}
+
 #[derive(Debug, thiserror::Error)]
 enum TemplateError {
     #[error("invalid template path: {0}")]
@@ -37,13 +137,27 @@ enum TemplateError {
 
     #[error("Invalid ID for image")]
     InvalidID,
+
+    #[error("upload exceeded the {0} byte size limit")]
+    UploadTooLarge(u64),
+
+    #[error("part '{0}' is not a supported image")]
+    UnsupportedPart(String),
 }
 
 #[derive(Serialize)]
 struct UploadResponse<'a> {
     src: &'a str,
+    params: DecayParams,
 }
 
+#[derive(Serialize)]
+struct BatchUploadEntry {
+    field_name: String,
+    src: String,
+}
+
+#[derive(Clone)]
 struct Image {
     mime: Mime,
     contents: Vec<u8>,
@@ -75,7 +189,7 @@ where
         let b: Box<dyn warp::Reply> = match self {
             Ok(reply) => Box::new(reply),
             Err(e) => {
-                log::error!("Error: {}", e);
+                tracing::error!(error = %e, "request failed");
                 let res = http::Response::builder()
                     .status(500)
                     .body("Something went wrong, sorry");
@@ -89,31 +203,46 @@ where
 trait BitCrush: Sized {
     type Error;
 
-    fn bitcrush(self) -> Result<Self, Self::Error>;
+    fn bitcrush(self, params: &DecayParams) -> Result<Self, Self::Error>;
 }
 
 impl BitCrush for DynamicImage {
     type Error = image::ImageError;
 
-    fn bitcrush(self) -> Result<Self, Self::Error> {
+    fn bitcrush(self, params: &DecayParams) -> Result<Self, Self::Error> {
         let mut current = self;
         let (orig_w, orig_h) = current.dimensions();
 
+        let span = tracing::info_span!(
+            "bitcrush",
+            width = orig_w,
+            height = orig_h,
+            passes = params.passes,
+        );
+        let _guard = span.enter();
+        let started = std::time::Instant::now();
+
         let mut rng = rand::thread_rng();
+        let (scale_min, scale_max) = (params.scale_min, params.scale_max);
         let (temp_w, temp_h) = (
-            rng.gen_range(orig_w / 2, orig_w * 2),
-            rng.gen_range(orig_h / 2, orig_h * 2),
+            ((orig_w as f32) * rng.gen_range(scale_min, scale_max)) as u32,
+            ((orig_h as f32) * rng.gen_range(scale_min, scale_max)) as u32,
         );
 
         let mut out: Vec<u8> = Default::default();
-        for _ in 0..2 {
-            current = current
-                .resize_exact(temp_w, temp_h, FilterType::Nearest)
-                .rotate180()
-                .huerotate(180);
+        for _ in 0..params.passes {
+            current = current.resize_exact(temp_w.max(1), temp_h.max(1), FilterType::Nearest);
+            if params.rotate180 {
+                current = current.rotate180();
+            }
+            if params.huerotate {
+                current = current.huerotate(180);
+            }
             out.clear();
             {
-                let mut encoder = JPEGEncoder::new_with_quality(&mut out, rng.gen_range(10, 30));
+                let quality = rng.gen_range(params.quality_min, params.quality_max + 1);
+                tracing::debug!(quality, "re-encoding pass");
+                let mut encoder = JPEGEncoder::new_with_quality(&mut out, quality);
                 encoder.encode_image(&current)?;
             }
 
@@ -121,6 +250,8 @@ impl BitCrush for DynamicImage {
                 .resize_exact(orig_w, orig_h, FilterType::Nearest);
         }
 
+        tracing::info!(duration_ms = started.elapsed().as_millis() as u64, "bitcrush complete");
+
         Ok(current)
     }
 }
@@ -143,6 +274,7 @@ async fn compile_templates(paths: &[&str]) -> Result<TemplateMap, Box<dyn Error>
     Ok(map)
 }
 
+#[tracing::instrument(skip(state, mime))]
 async fn serve_template(
     state: &State,
     name: &str,
@@ -168,7 +300,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
     }
-    pretty_env_logger::init();
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
 
     let templates = compile_templates(&[
         "./templates/index.html.liquid",
@@ -177,112 +311,293 @@ async fn main() -> Result<(), Box<dyn Error>> {
     ])
     .await?;
 
-    log::info!("{} templates compiled", templates.len());
+    tracing::info!(count = templates.len(), "templates compiled");
 
-    let state = State::new(templates);
+    let images = storage_from_env()?;
+    let upload_limits = upload_limits_from_env();
+    let state = State::new(templates, images, upload_limits);
     let state = Arc::new(state);
 
+    spawn_ttl_eviction(state.clone());
+
     let with_state = {
         let filter = warp::filters::any::any().map(move || state.clone());
         move || filter.clone()
     };
 
+    let with_request_span = || {
+        warp::filters::any::any().map(|| {
+            let request_id = Ulid::new();
+            tracing::info_span!("request", %request_id)
+        })
+    };
+
     let index = warp::filters::method::get()
         .and(warp::path::end())
         .and(with_state())
-        .and_then(|state: Arc<State>| async move {
-            serve_template(&state, "index.html", mimes::html())
-                .await
-                .for_warp()
+        .and(with_request_span())
+        .and_then(|state: Arc<State>, span: tracing::Span| {
+            async move {
+                serve_template(&state, "index.html", mimes::html())
+                    .await
+                    .for_warp()
+            }
+            .instrument(span)
         });
 
     let style = warp::filters::method::get()
         .and(warp::path!("style.css"))
         .and(with_state())
-        .and_then(|state: Arc<State>| async move {
-            serve_template(&state, "style.css", mimes::css())
-                .await
-                .for_warp()
+        .and(with_request_span())
+        .and_then(|state: Arc<State>, span: tracing::Span| {
+            async move {
+                serve_template(&state, "style.css", mimes::css())
+                    .await
+                    .for_warp()
+            }
+            .instrument(span)
         });
 
     let js = warp::filters::method::get()
         .and(warp::path!("main.js"))
         .and(with_state())
-        .and_then(|state: Arc<State>| async move {
-            serve_template(&state, "main.js", mimes::js())
-                .await
-                .for_warp()
+        .and(with_request_span())
+        .and_then(|state: Arc<State>, span: tracing::Span| {
+            async move {
+                serve_template(&state, "main.js", mimes::js())
+                    .await
+                    .for_warp()
+            }
+            .instrument(span)
         });
 
-    let upload = warp::filters::method::post()
+    // `warp::multipart::form()` rejects when the request's Content-Type
+    // isn't multipart (it needs the boundary parameter), so trying it
+    // first and falling back to the raw-body filter lets both the
+    // single-file and batch upload shapes share the same `/upload`
+    // endpoint instead of needing a separate route.
+    let upload_multipart = warp::filters::method::post()
         .and(warp::path("upload"))
+        .and(warp::path::end())
         .and(with_state())
-        .and(warp::filters::body::bytes())
-        .and_then(|state: Arc<State>, bytes: bytes::Bytes| async move {
-            handle_upload(&state, bytes).await.for_warp()
+        .and(warp::multipart::form().max_length(upload_limits.max_total_size))
+        .and(with_request_span())
+        .and_then(|state: Arc<State>, form: FormData, span: tracing::Span| {
+            async move { handle_batch_upload(&state, form).await.for_warp() }.instrument(span)
         });
 
+    let upload_single = warp::filters::method::post()
+        .and(warp::path("upload"))
+        .and(warp::path::end())
+        .and(with_state())
+        .and(warp::filters::query::query())
+        .and(warp::filters::body::bytes())
+        .and(with_request_span())
+        .and_then(
+            |state: Arc<State>,
+             query: HashMap<String, String>,
+             bytes: bytes::Bytes,
+             span: tracing::Span| {
+                async move { handle_upload(&state, bytes, query).await.for_warp() }
+                    .instrument(span)
+            },
+        );
+
+    let upload = upload_multipart.or(upload_single);
+
     let images = warp::filters::method::get()
         .and(warp::path!("images" / String))
         .and(with_state())
-        .and_then(|name: String, state: Arc<State>| async move {
-            serve_image(&state, &name).await.for_warp()
+        .and(warp::filters::header::optional::<String>("accept"))
+        .and(with_request_span())
+        .and_then(
+            |name: String, state: Arc<State>, accept: Option<String>, span: tracing::Span| {
+                async move {
+                    serve_image(&state, &name, accept.as_deref())
+                        .await
+                        .for_warp()
+                }
+                .instrument(span)
+            },
+        );
+
+    let purge = warp::filters::method::delete()
+        .and(warp::path!("images" / String))
+        .and(with_state())
+        .and(with_request_span())
+        .and_then(|name: String, state: Arc<State>, span: tracing::Span| {
+            async move { handle_purge(&state, &name).await.for_warp() }.instrument(span)
         });
 
     let addr: SocketAddr = "127.0.0.1:3000".parse()?;
-    warp::serve(index.or(style).or(js).or(upload).or(images))
-        .run(addr)
-        .await;
+    warp::serve(
+        index
+            .or(style)
+            .or(js)
+            .or(upload)
+            .or(images)
+            .or(purge),
+    )
+    .run(addr)
+    .await;
     Ok(())
 }
 
-async fn serve_image(state: &State, name: &str) -> Result<impl warp::Reply, Box<dyn Error>> {
+#[tracing::instrument(skip(state, accept), fields(id = tracing::field::Empty))]
+async fn serve_image(
+    state: &State,
+    name: &str,
+    accept: Option<&str>,
+) -> Result<impl warp::Reply, Box<dyn Error>> {
     let id: Ulid = name
         .trim_end_matches(".jpg")
         .parse()
         .map_err(|_| TemplateError::InvalidID)?;
+    tracing::Span::current().record("id", &tracing::field::display(&id));
+
+    let img = match state.images.get(id).await {
+        Some(img) => img,
+        None => {
+            let res: Box<dyn warp::Reply> = Box::new(
+                http::Response::builder()
+                    .status(404)
+                    .body("Image not found"),
+            );
+            return Ok(res);
+        }
+    };
 
-    let images = state.images.read().await;
-    let res: Box<dyn warp::Reply> = if let Some(img) = images.get(&id) {
-        Box::new(
-            http::Response::builder()
-                .content_type(img.mime.clone())
-                .body(img.contents.clone()),
-        )
+    // Transcoding only applies to the still-JPEG path: an animated GIF
+    // (or any other non-JPEG original) is served as-is so we don't
+    // silently flatten its frames or blow up trying to decode it as a
+    // still image.
+    let format = negotiate(accept);
+    let is_jpeg = img.mime.essence() == mimes::jpeg().essence();
+    let (mime, contents) = if !is_jpeg || format == OutputFormat::Jpeg {
+        (img.mime.clone(), img.contents.clone())
+    } else if let Some(cached) = state.images.get_variant(id, format).await {
+        (format.mime(), cached)
     } else {
-        Box::new(
-            http::Response::builder()
-                .status(404)
-                .body("Image not found"),
-        )
+        let decoded = image::load_from_memory(&img.contents)?;
+        let encoded = format.encode(&decoded)?;
+        state
+            .images
+            .put_variant(id, format, encoded.clone())
+            .await;
+        (format.mime(), encoded)
     };
 
+    let res: Box<dyn warp::Reply> =
+        Box::new(http::Response::builder().content_type(mime).body(contents));
+
+    Ok(res)
+}
+
+async fn handle_purge(state: &State, name: &str) -> Result<impl warp::Reply, Box<dyn Error>> {
+    let id: Ulid = name
+        .trim_end_matches(".jpg")
+        .parse()
+        .map_err(|_| TemplateError::InvalidID)?;
+
+    let status = if state.images.delete(id).await { 200 } else { 404 };
+    let res = http::Response::builder().status(status).body("");
     Ok(res)
 }
 
+/// Runs the degradation pipeline on one uploaded file's bytes and stores
+/// the result, returning the `src` path it was assigned. Shared by the
+/// single-file and multipart batch upload handlers.
+#[tracing::instrument(skip(state, bytes, params), fields(id = tracing::field::Empty))]
+async fn bitcrush_and_store(
+    state: &State,
+    bytes: &[u8],
+    params: &DecayParams,
+) -> Result<String, Box<dyn Error>> {
+    let id = Ulid::new();
+    tracing::Span::current().record("id", &tracing::field::display(&id));
+    let src = format!("/images/{}", id);
+
+    let img = if let Some(format) = detect_animated(bytes) {
+        let (contents, mime) = bitcrush_animated(format, bytes, params)?;
+        Image { mime, contents }
+    } else {
+        let decoded = image::load_from_memory(bytes)?.bitcrush(params)?;
+        let mut output: Vec<u8> = Default::default();
+        let mut encoder = JPEGEncoder::new_with_quality(&mut output, JPEG_QUALITY);
+        encoder.encode_image(&decoded)?;
+        Image {
+            mime: mimes::jpeg(),
+            contents: output,
+        }
+    };
+
+    state.images.put(id, &img).await;
+    Ok(src)
+}
+
+#[tracing::instrument(skip(state, bytes, query))]
 async fn handle_upload(
     state: &State,
     bytes: bytes::Bytes,
+    query: HashMap<String, String>,
 ) -> Result<impl warp::Reply, Box<dyn Error>> {
-    let img = image::load_from_memory(&bytes[..])?.bitcrush()?;
-    let mut output: Vec<u8> = Default::default();
-    let mut encoder = JPEGEncoder::new_with_quality(&mut output, JPEG_QUALITY);
-    encoder.encode_image(&img)?;
+    let params = DecayParams::from_query(&query);
+    params.validate()?;
 
-    let id = Ulid::new();
-    let src = format!("/images/{}", id);
+    let src = bitcrush_and_store(state, &bytes, &params).await?;
 
-    let img = Image {
-        mime: mimes::jpeg(),
-        contents: output,
-    };
+    let payload = serde_json::to_string(&UploadResponse { src: &src, params })?;
+    let res = http::Response::builder()
+        .content_type(mimes::json())
+        .body(payload);
+    Ok(res)
+}
 
-    {
-        let mut images = state.images.write().await;
-        images.insert(id, img);
+async fn handle_batch_upload(
+    state: &State,
+    form: FormData,
+) -> Result<impl warp::Reply, Box<dyn Error>> {
+    let limits = state.upload_limits;
+    let parts: Vec<warp::multipart::Part> = form.try_collect().await?;
+
+    let mut entries = Vec::with_capacity(parts.len());
+    let mut total_size: u64 = 0;
+
+    for mut part in parts {
+        let field_name = part.name().to_string();
+
+        // Enforce the per-part cap while streaming so an oversized part
+        // is rejected without ever buffering it in full.
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut stream = part.stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            let chunk_len = chunk.chunk().len() as u64;
+            bytes.extend_from_slice(chunk.chunk());
+
+            if bytes.len() as u64 > limits.max_part_size {
+                return Err(Box::new(TemplateError::UploadTooLarge(limits.max_part_size)));
+            }
+            total_size += chunk_len;
+            if total_size > limits.max_total_size {
+                return Err(Box::new(TemplateError::UploadTooLarge(limits.max_total_size)));
+            }
+        }
+
+        // `bitcrush_and_store` already has to decode the part to run the
+        // degradation pipeline, so let its decode double as the "is this
+        // a supported image" check rather than decoding it twice.
+        let src = match bitcrush_and_store(state, &bytes, &DecayParams::default()).await {
+            Ok(src) => src,
+            Err(e) if e.downcast_ref::<image::ImageError>().is_some() => {
+                return Err(Box::new(TemplateError::UnsupportedPart(field_name)));
+            }
+            Err(e) => return Err(e),
+        };
+        entries.push(BatchUploadEntry { field_name, src });
     }
 
-    let payload = serde_json::to_string(&UploadResponse { src: &src })?;
+    let payload = serde_json::to_string(&entries)?;
     let res = http::Response::builder()
         .content_type(mimes::json())
         .body(payload);
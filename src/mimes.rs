@@ -20,3 +20,23 @@ pub(crate) fn json() -> Mime {
 pub(crate) fn jpeg() -> Mime {
     mime::JPEG
 }
+
+pub(crate) fn webp() -> Mime {
+    Mime::from_str("image/webp").unwrap()
+}
+
+pub(crate) fn avif() -> Mime {
+    Mime::from_str("image/avif").unwrap()
+}
+
+pub(crate) fn jxl() -> Mime {
+    Mime::from_str("image/jxl").unwrap()
+}
+
+pub(crate) fn gif() -> Mime {
+    Mime::from_str("image/gif").unwrap()
+}
+
+pub(crate) fn mp4() -> Mime {
+    Mime::from_str("video/mp4").unwrap()
+}
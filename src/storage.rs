@@ -0,0 +1,181 @@
+use crate::format::OutputFormat;
+use crate::Image;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use ulid::Ulid;
+
+/// Abstracts over where bitcrushed images live so the service can run
+/// with a disposable in-memory map or durable object storage behind the
+/// same `State`. Transcoded variants (webp/avif/jxl) are cached
+/// alongside the original so repeat requests for the same format skip
+/// re-encoding.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, id: Ulid, img: &Image);
+    async fn get(&self, id: Ulid) -> Option<Image>;
+    async fn delete(&self, id: Ulid) -> bool;
+
+    async fn get_variant(&self, id: Ulid, format: OutputFormat) -> Option<Vec<u8>>;
+    async fn put_variant(&self, id: Ulid, format: OutputFormat, contents: Vec<u8>);
+
+    /// Lists every id currently held, so a TTL sweep can inspect each
+    /// Ulid's embedded creation timestamp without the backend needing to
+    /// track expiry itself.
+    async fn ids(&self) -> Vec<Ulid>;
+}
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    images: RwLock<HashMap<Ulid, Image>>,
+    variants: RwLock<HashMap<(Ulid, OutputFormat), Vec<u8>>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, id: Ulid, img: &Image) {
+        self.images.write().await.insert(id, img.clone());
+    }
+
+    async fn get(&self, id: Ulid) -> Option<Image> {
+        self.images.read().await.get(&id).cloned()
+    }
+
+    async fn delete(&self, id: Ulid) -> bool {
+        let removed = self.images.write().await.remove(&id).is_some();
+        let mut variants = self.variants.write().await;
+        for format in OutputFormat::ALL {
+            variants.remove(&(id, format));
+        }
+        removed
+    }
+
+    async fn get_variant(&self, id: Ulid, format: OutputFormat) -> Option<Vec<u8>> {
+        self.variants.read().await.get(&(id, format)).cloned()
+    }
+
+    async fn put_variant(&self, id: Ulid, format: OutputFormat, contents: Vec<u8>) {
+        self.variants.write().await.insert((id, format), contents);
+    }
+
+    async fn ids(&self) -> Vec<Ulid> {
+        self.images.read().await.keys().copied().collect()
+    }
+}
+
+/// Config needed to talk to an S3-compatible bucket (AWS S3, MinIO, etc).
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub endpoint: Option<String>,
+}
+
+pub struct S3Storage {
+    bucket: s3::bucket::Bucket,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Result<Self, s3::error::S3Error> {
+        let credentials = s3::creds::Credentials::new(
+            Some(&config.access_key),
+            Some(&config.secret_key),
+            None,
+            None,
+            None,
+        )?;
+
+        let region = match config.endpoint {
+            Some(endpoint) => s3::Region::Custom {
+                region: config.region,
+                endpoint,
+            },
+            None => config.region.parse()?,
+        };
+
+        let bucket = s3::bucket::Bucket::new(&config.bucket, region, credentials)?;
+        Ok(S3Storage { bucket })
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, id: Ulid, img: &Image) {
+        let key = format!("{}", id);
+        let content_type = img.mime.to_string();
+        if let Err(e) = self
+            .bucket
+            .put_object_with_content_type(&key, &img.contents, &content_type)
+            .await
+        {
+            tracing::error!(%id, error = %e, "failed to put image to S3");
+        }
+    }
+
+    async fn get(&self, id: Ulid) -> Option<Image> {
+        let key = format!("{}", id);
+        let (data, _) = self.bucket.get_object(&key).await.ok()?;
+        let mime = self
+            .bucket
+            .head_object(&key)
+            .await
+            .ok()
+            .and_then(|(head, _)| head.content_type)
+            .and_then(|ct| ct.parse().ok())
+            .unwrap_or_else(crate::mimes::jpeg);
+
+        Some(Image {
+            mime,
+            contents: data,
+        })
+    }
+
+    async fn delete(&self, id: Ulid) -> bool {
+        let key = format!("{}", id);
+        let removed = self.bucket.delete_object(&key).await.is_ok();
+
+        for format in OutputFormat::ALL {
+            let variant_key = format!("{}.{}", id, format.extension());
+            if let Err(e) = self.bucket.delete_object(&variant_key).await {
+                tracing::error!(key = %variant_key, error = %e, "failed to delete cached variant from S3");
+            }
+        }
+
+        removed
+    }
+
+    async fn get_variant(&self, id: Ulid, format: OutputFormat) -> Option<Vec<u8>> {
+        let key = format!("{}.{}", id, format.extension());
+        let (data, _) = self.bucket.get_object(&key).await.ok()?;
+        Some(data)
+    }
+
+    async fn put_variant(&self, id: Ulid, format: OutputFormat, contents: Vec<u8>) {
+        let key = format!("{}.{}", id, format.extension());
+        let content_type = format.mime().to_string();
+        if let Err(e) = self
+            .bucket
+            .put_object_with_content_type(&key, &contents, &content_type)
+            .await
+        {
+            tracing::error!(key = %key, error = %e, "failed to put image variant to S3");
+        }
+    }
+
+    async fn ids(&self) -> Vec<Ulid> {
+        let listing = match self.bucket.list("".to_string(), None).await {
+            Ok(listing) => listing,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to list S3 bucket contents");
+                return Vec::new();
+            }
+        };
+
+        listing
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|object| object.key.split('.').next()?.parse().ok())
+            .collect()
+    }
+}